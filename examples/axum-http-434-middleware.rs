@@ -1,24 +1,409 @@
 // examples/axum-http-434-middleware.rs
 
+use async_trait::async_trait;
 use axum::{
     body::Body,
-    http::{HeaderMap, Request, StatusCode},
+    extract::{Multipart, State},
+    http::{header::CONTENT_LENGTH, HeaderMap, Request, StatusCode},
     response::{IntoResponse, Response},
-    routing::get,
+    routing::{get, post},
     Json, Router,
 };
 use futures_util::future::BoxFuture;
-use serde::Serialize;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
-    collections::HashSet,
+    collections::HashMap,
     net::SocketAddr,
     sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tower::{Layer, Service};
 
-#[derive(Clone, Default)]
+/// Merkle paths with more sibling entries than this would only ever be
+/// padding or an attempt to make us hash a very large amount of
+/// attacker-controlled data.
+const MAX_MERKLE_PATH_ENTRIES: usize = 32;
+/// 32-byte sibling hash + 1 direction byte.
+const MERKLE_SIBLING_LEN: usize = 33;
+/// How far into the future an `X-ShadowPay-Issued-At` may claim to be before
+/// we treat it as bogus rather than as ordinary clock skew.
+const MAX_ISSUED_AT_SKEW_SECS: u64 = 60;
+
+/// Nullifiers seen so far, each tagged with the expiry of the proof that
+/// spent them. Expired entries are swept on every claim so the set is
+/// bounded by the active replay window instead of growing forever.
+#[derive(Default)]
+struct NullifierSet {
+    entries: HashMap<String, u64>,
+}
+
+impl NullifierSet {
+    /// Atomically checks-and-inserts `nullifier`. Returns `false` if it was
+    /// already present and still unexpired (a double-spend).
+    fn claim(&mut self, nullifier: &str, expiry: u64, now: u64) -> bool {
+        self.entries.retain(|_, exp| *exp > now);
+        if self.entries.contains_key(nullifier) {
+            return false;
+        }
+        self.entries.insert(nullifier.to_string(), expiry);
+        true
+    }
+}
+
+/// Lifecycle of an escrow account. `Released` means a refund has been
+/// issued; `Settled` means the original (non-refund) payment went through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EscrowState {
+    Locked,
+    Released,
+    Settled,
+}
+
+impl EscrowState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            EscrowState::Locked => "Locked",
+            EscrowState::Released => "Released",
+            EscrowState::Settled => "Settled",
+        }
+    }
+}
+
+#[derive(Clone)]
 struct ShadowPayState {
-    used_nullifiers: Arc<Mutex<HashSet<String>>>,
+    nullifiers: Arc<dyn NullifierStore>,
+    verifier: Arc<dyn Verifier>,
+    escrows: Arc<Mutex<HashMap<String, EscrowState>>>,
+}
+
+impl Default for ShadowPayState {
+    fn default() -> Self {
+        Self::with_verifier(Arc::new(StructuralVerifier))
+    }
+}
+
+impl ShadowPayState {
+    /// Builds state around a custom [`Verifier`], e.g. a `RetryingVerifier`
+    /// wrapping a client for an external zk-proof verification service.
+    /// Keeps the default single-process [`InMemoryNullifierStore`].
+    fn with_verifier(verifier: Arc<dyn Verifier>) -> Self {
+        Self::new(Arc::new(InMemoryNullifierStore::default()), verifier)
+    }
+
+    /// Builds state from explicit backends, e.g. a `RedisNullifierStore` so
+    /// double-spend protection holds across more than one instance.
+    fn new(nullifiers: Arc<dyn NullifierStore>, verifier: Arc<dyn Verifier>) -> Self {
+        let mut escrows = HashMap::new();
+        escrows.insert("LOCKED_ESCROW_FOR_DEMO".to_string(), EscrowState::Locked);
+        Self {
+            nullifiers,
+            verifier,
+            escrows: Arc::new(Mutex::new(escrows)),
+        }
+    }
+}
+
+/// A failure reaching the double-spend backend itself — distinct from a
+/// genuine double-spend, so callers don't mistake "Redis is down" for
+/// "this nullifier was already used".
+#[derive(Debug)]
+struct StoreError(String);
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+/// Double-spend guard backend. `claim` is the one operation implementations
+/// must make atomic: it returns `false` if `nullifier` was already present
+/// (and still unexpired), `true` if this call is the one that claimed it.
+#[async_trait]
+trait NullifierStore: Send + Sync {
+    async fn claim(&self, nullifier: &str, expiry: u64) -> Result<bool, StoreError>;
+}
+
+/// Single-process backend — the original behavior. Doesn't survive a
+/// restart and doesn't coordinate across more than one instance.
+#[derive(Default)]
+struct InMemoryNullifierStore {
+    entries: Mutex<NullifierSet>,
+}
+
+#[async_trait]
+impl NullifierStore for InMemoryNullifierStore {
+    async fn claim(&self, nullifier: &str, expiry: u64) -> Result<bool, StoreError> {
+        let mut guard = self.entries.lock().expect("nullifier mutex poisoned");
+        Ok(guard.claim(nullifier, expiry, current_unix_time()))
+    }
+}
+
+/// Shared backend for multi-instance deployments. `SET key val NX EX ttl`
+/// is itself the atomic claim: Redis only sets the key if absent, and
+/// expires it for us, which is also how the TTL eviction happens.
+struct RedisNullifierStore {
+    client: redis::Client,
+}
+
+impl RedisNullifierStore {
+    fn new(client: redis::Client) -> Self {
+        Self { client }
+    }
+
+    fn key(nullifier: &str) -> String {
+        format!("shadowpay:nullifier:{nullifier}")
+    }
+}
+
+#[async_trait]
+impl NullifierStore for RedisNullifierStore {
+    async fn claim(&self, nullifier: &str, expiry: u64) -> Result<bool, StoreError> {
+        let mut conn = self
+            .client
+            .get_async_connection()
+            .await
+            .map_err(|e| StoreError(e.to_string()))?;
+
+        let ttl = expiry.saturating_sub(current_unix_time()).max(1);
+        let claimed: Option<String> = redis::cmd("SET")
+            .arg(Self::key(nullifier))
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| StoreError(e.to_string()))?;
+
+        Ok(claimed.is_some())
+    }
+}
+
+/// Shared backend for deployments that would rather embed a database than
+/// run Redis. The `nullifier` column's `PRIMARY KEY` constraint is the
+/// atomic claim: a second insert for the same nullifier fails with a
+/// constraint violation rather than silently succeeding.
+struct SqliteNullifierStore {
+    conn: Mutex<rusqlite::Connection>,
+}
+
+impl SqliteNullifierStore {
+    fn new(conn: rusqlite::Connection) -> Result<Self, StoreError> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS shadowpay_nullifiers (
+                nullifier TEXT PRIMARY KEY,
+                expiry    INTEGER NOT NULL
+            )",
+            [],
+        )
+        .map_err(|e| StoreError(e.to_string()))?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+#[async_trait]
+impl NullifierStore for SqliteNullifierStore {
+    // rusqlite is synchronous; a real deployment would run this through
+    // `spawn_blocking` instead of holding the mutex across an `async fn`.
+    async fn claim(&self, nullifier: &str, expiry: u64) -> Result<bool, StoreError> {
+        let conn = self.conn.lock().expect("sqlite mutex poisoned");
+
+        conn.execute(
+            "DELETE FROM shadowpay_nullifiers WHERE expiry <= ?1",
+            [current_unix_time()],
+        )
+        .map_err(|e| StoreError(e.to_string()))?;
+
+        match conn.execute(
+            "INSERT INTO shadowpay_nullifiers (nullifier, expiry) VALUES (?1, ?2)",
+            rusqlite::params![nullifier, expiry],
+        ) {
+            Ok(_) => Ok(true),
+            Err(rusqlite::Error::SqliteFailure(e, _))
+                if e.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(StoreError(e.to_string())),
+        }
+    }
+}
+
+/// The headers a ShadowPay proof is carried in, parsed and ready to hand to
+/// a [`Verifier`]. Numeric fields have already been validated as well-formed
+/// (but not necessarily fresh or otherwise valid).
+#[derive(Clone)]
+struct ShadowPayHeaders {
+    proof: String,
+    nullifier: String,
+    merkle_root: String,
+    invoice_id: String,
+    merkle_path: String,
+    expiry: u64,
+    issued_at: u64,
+    escrow_account: Option<String>,
+}
+
+fn parse_shadowpay_headers(headers: &HeaderMap) -> Result<ShadowPayHeaders, VerifyError> {
+    let header_str = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_string()
+    };
+
+    let proof = header_str("X-ShadowPay-Proof");
+    let nullifier = header_str("X-ShadowPay-Nullifier");
+    let merkle_root = header_str("X-ShadowPay-Merkle-Root");
+    let invoice_id = header_str("X-ShadowPay-Invoice-Id");
+    let merkle_path = header_str("X-ShadowPay-Merkle-Path");
+    let expiry_raw = header_str("X-ShadowPay-Expiry");
+    let issued_at_raw = header_str("X-ShadowPay-Issued-At");
+    let escrow_account = headers
+        .get("X-ShadowPay-Escrow-Account")
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.to_string());
+
+    if proof.is_empty()
+        || nullifier.is_empty()
+        || merkle_root.is_empty()
+        || invoice_id.is_empty()
+        || expiry_raw.is_empty()
+        || issued_at_raw.is_empty()
+    {
+        return Err(VerifyError::MissingHeaders);
+    }
+
+    let expiry: u64 = expiry_raw
+        .parse()
+        .map_err(|_| VerifyError::InvalidProof("Expiry must be a unix timestamp".into()))?;
+    let issued_at: u64 = issued_at_raw
+        .parse()
+        .map_err(|_| VerifyError::InvalidProof("Issued-at must be a unix timestamp".into()))?;
+
+    Ok(ShadowPayHeaders {
+        proof,
+        nullifier,
+        merkle_root,
+        invoice_id,
+        merkle_path,
+        expiry,
+        issued_at,
+        escrow_account,
+    })
+}
+
+/// Verifies that a ShadowPay proof is valid. Implementations may check
+/// structure inline (see [`StructuralVerifier`]) or call out to an external
+/// zk-proof verification service.
+#[async_trait]
+trait Verifier: Send + Sync {
+    async fn verify(&self, headers: &ShadowPayHeaders) -> Result<(), VerifyError>;
+}
+
+/// The demo verifier: runs the structural, Merkle-inclusion and freshness
+/// checks in-process, with no external calls.
+#[derive(Clone, Default)]
+struct StructuralVerifier;
+
+#[async_trait]
+impl Verifier for StructuralVerifier {
+    async fn verify(&self, headers: &ShadowPayHeaders) -> Result<(), VerifyError> {
+        if !looks_like_base64(&headers.proof) {
+            return Err(VerifyError::InvalidProof("Proof is not valid base64".into()));
+        }
+
+        if headers.invoice_id != "inv_demo_1" {
+            return Err(VerifyError::PreconditionMissing(
+                "Unknown or inactive invoice id".into(),
+            ));
+        }
+
+        verify_merkle_inclusion(
+            &headers.invoice_id,
+            &headers.nullifier,
+            &headers.merkle_root,
+            &headers.merkle_path,
+        )?;
+
+        if headers.nullifier.len() < 16 {
+            return Err(VerifyError::InvalidProof(
+                "Nullifier looks too short".into(),
+            ));
+        }
+
+        let now = current_unix_time();
+        if headers.expiry <= now {
+            return Err(VerifyError::InvalidProof("Proof has expired".into()));
+        }
+        if headers.issued_at > now + MAX_ISSUED_AT_SKEW_SECS {
+            return Err(VerifyError::InvalidProof(
+                "Proof issued-at is too far in the future".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Backoff policy for [`RetryingVerifier`], modeled on fuels-rs's retryable
+/// client: a fixed attempt budget and a base delay that doubles each retry,
+/// with optional jitter to avoid thundering-herd retries against the prover.
+#[derive(Clone, Copy)]
+struct RetryConfig {
+    max_attempts: u32,
+    base_delay: Duration,
+    jitter: bool,
+}
+
+impl RetryConfig {
+    fn backoff_delay(&self, attempt: u32) -> Duration {
+        let delay = self.base_delay.saturating_mul(2u32.saturating_pow(attempt));
+        if self.jitter {
+            let jitter_ms = rand::thread_rng().gen_range(0..=(delay.as_millis() as u64 / 2).max(1));
+            delay + Duration::from_millis(jitter_ms)
+        } else {
+            delay
+        }
+    }
+}
+
+/// Wraps a [`Verifier`] and retries it with exponential backoff when it
+/// fails with a transient error (e.g. the external prover timed out).
+/// `DoubleSpend` and `InvalidProof` are terminal and never retried.
+struct RetryingVerifier<V> {
+    inner: V,
+    config: RetryConfig,
+}
+
+impl<V> RetryingVerifier<V> {
+    fn new(inner: V, config: RetryConfig) -> Self {
+        Self { inner, config }
+    }
+}
+
+#[async_trait]
+impl<V: Verifier> Verifier for RetryingVerifier<V> {
+    async fn verify(&self, headers: &ShadowPayHeaders) -> Result<(), VerifyError> {
+        let mut attempt = 0;
+        loop {
+            match self.inner.verify(headers).await {
+                Ok(()) => return Ok(()),
+                Err(err) if err.is_transient() && attempt + 1 < self.config.max_attempts => {
+                    tokio::time::sleep(self.config.backoff_delay(attempt)).await;
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -27,10 +412,8 @@ struct ShadowPayLayer {
 }
 
 impl ShadowPayLayer {
-    fn new() -> Self {
-        Self {
-            state: ShadowPayState::default(),
-        }
+    fn new(state: ShadowPayState) -> Self {
+        Self { state }
     }
 }
 
@@ -56,8 +439,22 @@ enum VerifyError {
     MissingHeaders,
     InvalidProof(String),
     DoubleSpend(String),
-    EscrowLocked,
+    /// Carries the escrow's current state so the 423 response can surface it.
+    EscrowLocked(EscrowState),
     PreconditionMissing(String),
+    /// A transient/IO failure talking to an external verifier — safe to
+    /// retry, unlike a terminal `InvalidProof` or `DoubleSpend`.
+    Transient(String),
+    /// The nullifier store itself failed (Redis unreachable, SQLite locked,
+    /// ...). Distinct from `DoubleSpend` so we don't mistake "we don't know"
+    /// for "this was already used".
+    StoreUnavailable(String),
+}
+
+impl VerifyError {
+    fn is_transient(&self) -> bool {
+        matches!(self, VerifyError::Transient(_))
+    }
 }
 
 #[derive(Serialize)]
@@ -72,6 +469,15 @@ fn has_shadowpay_headers(headers: &HeaderMap) -> bool {
         && headers.contains_key("X-ShadowPay-Nullifier")
         && headers.contains_key("X-ShadowPay-Merkle-Root")
         && headers.contains_key("X-ShadowPay-Invoice-Id")
+        && headers.contains_key("X-ShadowPay-Expiry")
+        && headers.contains_key("X-ShadowPay-Issued-At")
+}
+
+fn current_unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock before unix epoch")
+        .as_secs()
 }
 
 fn looks_like_base64(s: &str) -> bool {
@@ -82,82 +488,162 @@ fn looks_like_base64(s: &str) -> bool {
     base64::decode(clean).is_ok()
 }
 
-fn looks_like_hex32(s: &str) -> bool {
+fn decode_hex32(s: &str) -> Option<[u8; 32]> {
     let clean = s.trim();
-    clean.len() == 64 && clean.chars().all(|c| c.is_ascii_hexdigit())
+    if clean.len() != 64 {
+        return None;
+    }
+    let mut out = [0u8; 32];
+    for (i, chunk) in clean.as_bytes().chunks(2).enumerate() {
+        let hi = (chunk[0] as char).to_digit(16)?;
+        let lo = (chunk[1] as char).to_digit(16)?;
+        out[i] = ((hi << 4) | lo) as u8;
+    }
+    Some(out)
 }
 
-// Demo verifier that does real structural checks and a nullifier set.
-fn verify_shadowpay(headers: &HeaderMap, state: &ShadowPayState) -> Result<(), VerifyError> {
-    let proof = headers
-        .get("X-ShadowPay-Proof")
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("");
-    let nullifier = headers
-        .get("X-ShadowPay-Nullifier")
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("");
-    let merkle_root = headers
-        .get("X-ShadowPay-Merkle-Root")
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("");
-    let invoice_id = headers
-        .get("X-ShadowPay-Invoice-Id")
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("");
-    let escrow_account = headers
-        .get("X-ShadowPay-Escrow-Account")
-        .and_then(|v| v.to_str().ok());
-    let _scheme = headers
-        .get("X-ShadowPay-Scheme")
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("shadowpay_v1");
+/// Tagged hash as used by Lightning's offer/bolt12 merkle signing:
+/// `SHA256(SHA256(tag) || SHA256(tag) || msg)`. Domain-separates the leaf
+/// and branch hashes so one can't be mistaken for the other.
+fn tagged_hash(tag: &str, msg: &[u8]) -> [u8; 32] {
+    let tag_hash = Sha256::digest(tag.as_bytes());
+    let mut hasher = Sha256::new();
+    hasher.update(tag_hash);
+    hasher.update(tag_hash);
+    hasher.update(msg);
+    hasher.finalize().into()
+}
 
-    if proof.is_empty() || nullifier.is_empty() || merkle_root.is_empty() || invoice_id.is_empty() {
-        return Err(VerifyError::MissingHeaders);
-    }
+fn commitment_bytes(invoice_id: &str, nullifier: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(invoice_id.len() + nullifier.len() + 1);
+    bytes.extend_from_slice(invoice_id.as_bytes());
+    bytes.push(0); // separator so a shifted boundary can't produce the same bytes
+    bytes.extend_from_slice(nullifier.as_bytes());
+    bytes
+}
 
-    if invoice_id != "inv_demo_1" {
-        return Err(VerifyError::PreconditionMissing(
-            "Unknown or inactive invoice id".into(),
-        ));
+struct MerkleSibling {
+    hash: [u8; 32],
+    sibling_is_left: bool,
+}
+
+fn parse_merkle_path(encoded: &str) -> Result<Vec<MerkleSibling>, VerifyError> {
+    let clean = encoded.trim();
+    if clean.is_empty() {
+        return Ok(Vec::new());
     }
 
-    if !looks_like_base64(proof) {
-        return Err(VerifyError::InvalidProof("Proof is not valid base64".into()));
+    let raw = base64::decode(clean)
+        .map_err(|_| VerifyError::InvalidProof("Merkle path is not valid base64".into()))?;
+
+    if raw.len() % MERKLE_SIBLING_LEN != 0 {
+        return Err(VerifyError::InvalidProof(
+            "Merkle path entries must each be a 32-byte hash plus a direction byte".into(),
+        ));
     }
 
-    if !looks_like_hex32(merkle_root) {
+    if raw.len() / MERKLE_SIBLING_LEN > MAX_MERKLE_PATH_ENTRIES {
         return Err(VerifyError::InvalidProof(
-            "Merkle root must be 32 byte hex".into(),
+            "Merkle path exceeds the maximum supported depth".into(),
         ));
     }
 
-    if nullifier.len() < 16 {
+    raw.chunks(MERKLE_SIBLING_LEN)
+        .map(|chunk| {
+            let mut hash = [0u8; 32];
+            hash.copy_from_slice(&chunk[..32]);
+            match chunk[32] {
+                0 => Ok(MerkleSibling {
+                    hash,
+                    sibling_is_left: false,
+                }),
+                1 => Ok(MerkleSibling {
+                    hash,
+                    sibling_is_left: true,
+                }),
+                _ => Err(VerifyError::InvalidProof(
+                    "Merkle path direction bit must be 0 or 1".into(),
+                )),
+            }
+        })
+        .collect()
+}
+
+/// Recomputes the Merkle root from the invoice/nullifier commitment and the
+/// supplied sibling path, then checks it against the advertised root. An
+/// empty path means the leaf itself must equal the root.
+fn verify_merkle_inclusion(
+    invoice_id: &str,
+    nullifier: &str,
+    merkle_root: &str,
+    merkle_path: &str,
+) -> Result<(), VerifyError> {
+    let root = decode_hex32(merkle_root)
+        .ok_or_else(|| VerifyError::InvalidProof("Merkle root must be 32 byte hex".into()))?;
+    let path = parse_merkle_path(merkle_path)?;
+
+    let mut node = tagged_hash("ShadowPayLeaf", &commitment_bytes(invoice_id, nullifier));
+    for sibling in &path {
+        let mut msg = Vec::with_capacity(64);
+        if sibling.sibling_is_left {
+            msg.extend_from_slice(&sibling.hash);
+            msg.extend_from_slice(&node);
+        } else {
+            msg.extend_from_slice(&node);
+            msg.extend_from_slice(&sibling.hash);
+        }
+        node = tagged_hash("ShadowPayBranch", &msg);
+    }
+
+    if node != root {
         return Err(VerifyError::InvalidProof(
-            "Nullifier looks too short".into(),
+            "Merkle path does not resolve to the advertised root".into(),
         ));
     }
 
-    if let Some(acc) = escrow_account {
-        if acc == "LOCKED_ESCROW_FOR_DEMO" {
-            return Err(VerifyError::EscrowLocked);
+    Ok(())
+}
+
+/// Parses the ShadowPay headers and runs them through [`verify_shadowpay_proof`].
+async fn verify_shadowpay(headers: &HeaderMap, state: &ShadowPayState) -> Result<(), VerifyError> {
+    let parsed = parse_shadowpay_headers(headers)?;
+    verify_shadowpay_proof(&parsed, state).await
+}
+
+/// Hands already-parsed ShadowPay fields to `state`'s configured
+/// [`Verifier`], and — once the proof itself checks out — claims the
+/// nullifier against the configured [`NullifierStore`]. Shared by the
+/// header-based middleware and the multipart submission endpoint.
+async fn verify_shadowpay_proof(
+    parsed: &ShadowPayHeaders,
+    state: &ShadowPayState,
+) -> Result<(), VerifyError> {
+    state.verifier.verify(parsed).await?;
+
+    if let Some(acc) = &parsed.escrow_account {
+        let escrow_state = state
+            .escrows
+            .lock()
+            .expect("escrow mutex poisoned")
+            .get(acc)
+            .copied();
+        if let Some(EscrowState::Locked) = escrow_state {
+            return Err(VerifyError::EscrowLocked(EscrowState::Locked));
         }
     }
 
-    let mut guard = state
-        .used_nullifiers
-        .lock()
-        .expect("nullifier mutex poisoned");
+    let claimed = state
+        .nullifiers
+        .claim(&parsed.nullifier, parsed.expiry)
+        .await
+        .map_err(|e| VerifyError::StoreUnavailable(e.to_string()))?;
 
-    if guard.contains(nullifier) {
+    if !claimed {
         return Err(VerifyError::DoubleSpend(
             "Nullifier already used".into(),
         ));
     }
 
-    guard.insert(nullifier.to_string());
-
     Ok(())
 }
 
@@ -201,58 +687,385 @@ where
                 return Ok(resp);
             }
 
-            match verify_shadowpay(headers, &state) {
+            match verify_shadowpay(headers, &state).await {
                 Ok(()) => inner.call(req).await,
-                Err(VerifyError::MissingHeaders) => {
-                    let body = ErrorBody {
-                        status: 434,
-                        title: "Private Payment Proof Required",
-                        detail: "ShadowPay proof headers are incomplete.".into(),
-                    };
-                    let resp = (StatusCode::from_u16(434).unwrap(), Json(body)).into_response();
-                    Ok(resp)
-                }
-                Err(VerifyError::InvalidProof(msg)) => {
-                    let body = ErrorBody {
-                        status: 422,
-                        title: "Invalid ShadowPay Proof",
-                        detail: msg,
-                    };
-                    let resp = (StatusCode::UNPROCESSABLE_ENTITY, Json(body)).into_response();
-                    Ok(resp)
-                }
-                Err(VerifyError::DoubleSpend(msg)) => {
-                    let body = ErrorBody {
-                        status: 409,
-                        title: "ShadowPay Nullifier Conflict",
-                        detail: msg,
-                    };
-                    let resp = (StatusCode::CONFLICT, Json(body)).into_response();
-                    Ok(resp)
-                }
-                Err(VerifyError::EscrowLocked) => {
-                    let body = ErrorBody {
-                        status: 423,
-                        title: "ShadowPay Escrow Locked",
-                        detail: "Escrow account is locked for this demo.".into(),
-                    };
-                    let resp = (StatusCode::LOCKED, Json(body)).into_response();
-                    Ok(resp)
-                }
-                Err(VerifyError::PreconditionMissing(msg)) => {
-                    let body = ErrorBody {
-                        status: 428,
-                        title: "ShadowPay Precondition Required",
-                        detail: msg,
-                    };
-                    let resp = (StatusCode::PRECONDITION_REQUIRED, Json(body)).into_response();
-                    Ok(resp)
-                }
+                Err(err) => Ok(verify_error_response(err)),
             }
         })
     }
 }
 
+fn problem_response(status: StatusCode, title: &str, detail: String) -> Response {
+    let body = ErrorBody {
+        status: status.as_u16(),
+        title,
+        detail,
+    };
+    (status, Json(body)).into_response()
+}
+
+/// Maps a [`VerifyError`] to its problem-body response. Shared by the
+/// header-based middleware and the multipart submission endpoint so both
+/// entry points report failures the same way.
+fn verify_error_response(err: VerifyError) -> Response {
+    match err {
+        VerifyError::MissingHeaders => problem_response(
+            StatusCode::from_u16(434).unwrap(),
+            "Private Payment Proof Required",
+            "ShadowPay proof headers are incomplete.".into(),
+        ),
+        VerifyError::InvalidProof(msg) => {
+            problem_response(StatusCode::UNPROCESSABLE_ENTITY, "Invalid ShadowPay Proof", msg)
+        }
+        VerifyError::DoubleSpend(msg) => {
+            problem_response(StatusCode::CONFLICT, "ShadowPay Nullifier Conflict", msg)
+        }
+        VerifyError::EscrowLocked(escrow_state) => problem_response(
+            StatusCode::LOCKED,
+            "ShadowPay Escrow Locked",
+            format!("Escrow account is {}.", escrow_state.as_str()),
+        ),
+        VerifyError::PreconditionMissing(msg) => problem_response(
+            StatusCode::PRECONDITION_REQUIRED,
+            "ShadowPay Precondition Required",
+            msg,
+        ),
+        VerifyError::Transient(msg) => problem_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "ShadowPay Verifier Unavailable",
+            msg,
+        ),
+        VerifyError::StoreUnavailable(msg) => problem_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "ShadowPay Nullifier Store Unavailable",
+            msg,
+        ),
+    }
+}
+
+/// Cap on a multipart proof submission. A declared `Content-Length` over the
+/// cap is rejected before any field is buffered; otherwise each field is read
+/// in full and the running total is checked after it, so a request without
+/// `Content-Length` can have one oversized field buffered before rejection.
+const MAX_SUBMISSION_BODY_BYTES: usize = 64 * 1024;
+
+/// An S3-PostObject-style policy document, base64-JSON-encoded in the
+/// `policy` form field. Bounds what a submission is allowed to claim before
+/// it ever reaches the `Verifier`. The document carries no signature and its
+/// authenticity is not checked; only its conditions are.
+#[derive(Deserialize)]
+struct SubmissionPolicy {
+    invoice_id: String,
+    min_amount: Option<u64>,
+    max_amount: Option<u64>,
+    not_after: u64,
+}
+
+fn check_submission_policy(
+    policy: &SubmissionPolicy,
+    invoice_id: &str,
+    amount: u64,
+    now: u64,
+) -> Result<(), String> {
+    if policy.invoice_id != invoice_id {
+        return Err("Policy invoice id does not match the submitted invoice id".into());
+    }
+    if let Some(min_amount) = policy.min_amount {
+        if amount < min_amount {
+            return Err("Amount is below the policy minimum".into());
+        }
+    }
+    if let Some(max_amount) = policy.max_amount {
+        if amount > max_amount {
+            return Err("Amount is above the policy maximum".into());
+        }
+    }
+    if now > policy.not_after {
+        return Err("Submission policy has expired".into());
+    }
+    Ok(())
+}
+
+/// Builds [`ShadowPayHeaders`] from multipart form fields instead of
+/// request headers, for clients that can't set custom headers. Applies the
+/// same presence and well-formedness rules as [`parse_shadowpay_headers`].
+fn parse_shadowpay_submission_fields(
+    fields: &HashMap<String, String>,
+) -> Result<ShadowPayHeaders, VerifyError> {
+    let field = |name: &str| fields.get(name).cloned().unwrap_or_default();
+
+    let proof = field("proof");
+    let nullifier = field("nullifier");
+    let merkle_root = field("merkle_root");
+    let invoice_id = field("invoice_id");
+    let merkle_path = field("merkle_path");
+    let expiry_raw = field("expiry");
+    let issued_at_raw = field("issued_at");
+    let escrow_account = fields.get("escrow_account").cloned();
+
+    if proof.is_empty()
+        || nullifier.is_empty()
+        || merkle_root.is_empty()
+        || invoice_id.is_empty()
+        || expiry_raw.is_empty()
+        || issued_at_raw.is_empty()
+    {
+        return Err(VerifyError::MissingHeaders);
+    }
+
+    let expiry: u64 = expiry_raw
+        .parse()
+        .map_err(|_| VerifyError::InvalidProof("Expiry must be a unix timestamp".into()))?;
+    let issued_at: u64 = issued_at_raw
+        .parse()
+        .map_err(|_| VerifyError::InvalidProof("Issued-at must be a unix timestamp".into()))?;
+
+    Ok(ShadowPayHeaders {
+        proof,
+        nullifier,
+        merkle_root,
+        invoice_id,
+        merkle_path,
+        expiry,
+        issued_at,
+        escrow_account,
+    })
+}
+
+/// POST multipart/form-data endpoint for browsers/forms that can't set the
+/// `X-ShadowPay-*` headers, modeled on S3's PostObject: the client submits
+/// a policy document alongside the proof fields, and we check both the proof
+/// and the policy's conditions before accepting it.
+async fn submit_proof_handler(
+    State(state): State<ShadowPayState>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
+    if let Some(len) = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok())
+    {
+        if len > MAX_SUBMISSION_BODY_BYTES {
+            return problem_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "ShadowPay Submission Too Large",
+                format!("Submission body of {len} bytes exceeds the {MAX_SUBMISSION_BODY_BYTES} byte limit"),
+            );
+        }
+    }
+
+    let mut fields = HashMap::new();
+    let mut buffered_bytes = 0usize;
+
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(err) => {
+                return problem_response(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "Invalid ShadowPay Submission",
+                    format!("Malformed multipart body: {err}"),
+                );
+            }
+        };
+        let name = field.name().unwrap_or_default().to_string();
+        let bytes = match field.bytes().await {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                return problem_response(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "Invalid ShadowPay Submission",
+                    format!("Malformed multipart field {name:?}: {err}"),
+                );
+            }
+        };
+
+        buffered_bytes += bytes.len();
+        if buffered_bytes > MAX_SUBMISSION_BODY_BYTES {
+            return problem_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "ShadowPay Submission Too Large",
+                format!("Submission body exceeds the {MAX_SUBMISSION_BODY_BYTES} byte limit"),
+            );
+        }
+
+        fields.insert(name, String::from_utf8_lossy(&bytes).into_owned());
+    }
+
+    let parsed = match parse_shadowpay_submission_fields(&fields) {
+        Ok(parsed) => parsed,
+        Err(err) => return verify_error_response(err),
+    };
+
+    let amount: u64 = match fields.get("amount").and_then(|v| v.parse().ok()) {
+        Some(amount) => amount,
+        None => {
+            return problem_response(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Invalid ShadowPay Submission",
+                "Missing or invalid amount field".into(),
+            );
+        }
+    };
+
+    let policy = fields
+        .get("policy")
+        .ok_or(())
+        .and_then(|encoded| base64::decode(encoded).map_err(|_| ()))
+        .and_then(|raw| serde_json::from_slice::<SubmissionPolicy>(&raw).map_err(|_| ()));
+    let policy = match policy {
+        Ok(policy) => policy,
+        Err(()) => {
+            return problem_response(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Invalid ShadowPay Submission",
+                "Policy field must be base64-encoded JSON".into(),
+            );
+        }
+    };
+
+    if let Err(detail) =
+        check_submission_policy(&policy, &parsed.invoice_id, amount, current_unix_time())
+    {
+        return problem_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "ShadowPay Policy Rejected",
+            detail,
+        );
+    }
+
+    match verify_shadowpay_proof(&parsed, &state).await {
+        Ok(()) => Json(serde_json::json!({ "status": "accepted" })).into_response(),
+        Err(err) => verify_error_response(err),
+    }
+}
+
+/// A refund request against a locked escrow, modeled on Lightning's refund
+/// flow: the payer metadata the request carries must be echoed back
+/// unchanged in the refund acknowledgement.
+#[derive(Deserialize)]
+struct RefundRequest {
+    escrow_account: String,
+    invoice_id: String,
+    issuer: String,
+    payer_metadata: String,
+    amount: u64,
+    absolute_expiry: u64,
+}
+
+#[derive(Serialize)]
+struct RefundAck {
+    invoice_id: String,
+    issuer: String,
+    payer_metadata: String,
+    amount: u64,
+    commitment: String,
+    escrow_state: &'static str,
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn refund_commitment_bytes(invoice_id: &str, payer_metadata: &str, issuer: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(invoice_id.len() + payer_metadata.len() + issuer.len() + 2);
+    bytes.extend_from_slice(invoice_id.as_bytes());
+    bytes.push(0);
+    bytes.extend_from_slice(payer_metadata.as_bytes());
+    bytes.push(0);
+    bytes.extend_from_slice(issuer.as_bytes());
+    bytes
+}
+
+/// Releases a locked escrow and mints a refund acknowledgement. The refund
+/// nullifier (shared with the proof-side [`NullifierStore`], under a
+/// `refund:` prefix) makes a second claim against the same escrow fail
+/// rather than double-release it.
+async fn refund_handler(
+    State(state): State<ShadowPayState>,
+    Json(req): Json<RefundRequest>,
+) -> Response {
+    let now = current_unix_time();
+    if req.absolute_expiry <= now {
+        return problem_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Invalid ShadowPay Refund",
+            "Refund request has expired".into(),
+        );
+    }
+
+    let current_state = state
+        .escrows
+        .lock()
+        .expect("escrow mutex poisoned")
+        .get(&req.escrow_account)
+        .copied();
+
+    match current_state {
+        None => {
+            return problem_response(
+                StatusCode::NOT_FOUND,
+                "Unknown ShadowPay Escrow",
+                "Escrow account does not exist.".into(),
+            );
+        }
+        Some(EscrowState::Locked) => {}
+        Some(other) => {
+            return problem_response(
+                StatusCode::CONFLICT,
+                "ShadowPay Escrow Not Locked",
+                format!("Escrow account is {} and cannot be refunded.", other.as_str()),
+            );
+        }
+    }
+
+    let refund_nullifier = format!("refund:{}", req.escrow_account);
+    let claimed = match state
+        .nullifiers
+        .claim(&refund_nullifier, req.absolute_expiry)
+        .await
+    {
+        Ok(claimed) => claimed,
+        Err(err) => {
+            return problem_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "ShadowPay Nullifier Store Unavailable",
+                err.to_string(),
+            );
+        }
+    };
+    if !claimed {
+        return problem_response(
+            StatusCode::CONFLICT,
+            "ShadowPay Refund Already Claimed",
+            "This escrow has already been refunded.".into(),
+        );
+    }
+
+    state
+        .escrows
+        .lock()
+        .expect("escrow mutex poisoned")
+        .insert(req.escrow_account.clone(), EscrowState::Released);
+
+    let commitment = tagged_hash(
+        "ShadowPayRefund",
+        &refund_commitment_bytes(&req.invoice_id, &req.payer_metadata, &req.issuer),
+    );
+
+    Json(RefundAck {
+        invoice_id: req.invoice_id,
+        issuer: req.issuer,
+        payer_metadata: req.payer_metadata,
+        amount: req.amount,
+        commitment: hex_encode(&commitment),
+        escrow_state: EscrowState::Released.as_str(),
+    })
+    .into_response()
+}
+
 async fn public_handler() -> impl IntoResponse {
     Json(serde_json::json!({
         "data": "public ok"
@@ -276,11 +1089,16 @@ async fn demo_invoice_handler() -> impl IntoResponse {
 
 #[tokio::main]
 async fn main() {
+    let state = ShadowPayState::default();
+
     let app = Router::new()
         .route("/v1/public", get(public_handler))
         .route("/v1/protected", get(protected_handler))
         .route("/v1/demo-invoice", get(demo_invoice_handler))
-        .layer(ShadowPayLayer::new());
+        .route("/v1/shadowpay/submit", post(submit_proof_handler))
+        .route("/v1/shadowpay/refund", post(refund_handler))
+        .layer(ShadowPayLayer::new(state.clone()))
+        .with_state(state);
 
     let addr: SocketAddr = "127.0.0.1:3000".parse().unwrap();
     println!("ShadowPay 434 Axum demo on http://{}", addr);